@@ -4,9 +4,13 @@ extern crate docopt;
 extern crate num;
 
 use std::io::prelude::*;
+use std::io;
 use std::fs::File;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::default::Default;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process;
 
 use docopt::Docopt;
 use pest::prelude::*;
@@ -15,16 +19,40 @@ use num::Zero;
 
 const USAGE: &'static str = "
 Usage:
-  brusque [-v] <tm2>
+  brusque run [-v] [--detect-loops] [--dump-tape] [<tm2>]
+  brusque check [<tm2>]
   brusque -h | --help
 
 Options:
-  -h --help  Show this screen.
-  -v         Print all states
+  -h --help       Show this screen.
+  -v              Print all states
+  --detect-loops  Detect non-halting machines via cycle finding
+  --dump-tape     Print the final non-blank tape region as a run-length string
+
+<tm2> is read from stdin when omitted or given as '-'.
 ";
 
+const RESERVED_NAMES: [&'static str; 5] = ["HALT", "ERROR", "REJECT", "OUT", "ACCEPT"];
+
+// The alphabet is restricted to single lowercase letters by the grammar, so
+// it can never express more symbols than there are letters.
+const MAX_SYMBOLS: usize = 26;
+
 type StateNumber = usize;
 
+/// An index into the machine's k-symbol alphabet. Symbol 0 (`a`) is blank.
+pub type Symbol = usize;
+
+const BLANK: Symbol = 0;
+
+fn symbol_from_char(c: char) -> Symbol {
+    (c as u8 - b'a') as Symbol
+}
+
+fn char_from_symbol(s: Symbol) -> char {
+    (b'a' + s as u8) as char
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Direction {
     L,
@@ -32,14 +60,9 @@ pub enum Direction {
     None,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum Symbol {
-    A,
-    B,
-}
-
 #[derive(Debug)]
 pub struct TransitionInfo {
+    read: Symbol,
     next: String,
     mov: Direction,
     write: Symbol,
@@ -47,12 +70,25 @@ pub struct TransitionInfo {
 
 #[derive(Debug)]
 pub struct StateInfo {
-    on_a: TransitionInfo,
-    on_b: TransitionInfo,
+    transitions: Vec<TransitionInfo>,
     start: bool,
     name: String
 }
 
+/// An `include "path" as PREFIX -> EXIT` directive: pulls in another file's
+/// states under the `PREFIX.` namespace, rewriting any of its transitions
+/// that would have left the subroutine (to HALT/ERROR/REJECT/OUT/ACCEPT) to
+/// jump to the including machine's `EXIT` state instead. The included file's
+/// own START state is exposed to callers as `PREFIX` itself, so a caller
+/// need only know the declared `PREFIX`/`EXIT` interface, not the
+/// subroutine's internal state names.
+#[derive(Debug)]
+pub struct IncludeDirective {
+    path: String,
+    prefix: String,
+    exit: String,
+}
+
 #[derive(Debug)]
 pub struct Transition {
     next: StateNumber,
@@ -62,8 +98,7 @@ pub struct Transition {
 
 #[derive(Debug)]
 pub struct State {
-    on_a: Transition,
-    on_b: Transition,
+    transitions: Vec<Transition>,
 }
 
 #[derive(Debug, Default)]
@@ -72,6 +107,75 @@ pub struct Tm {
     start_state: Option<StateNumber>,
 }
 
+#[derive(Debug)]
+pub enum ValidationError {
+    StateCountMismatch { declared: usize, parsed: usize },
+    AlphabetTooLarge { declared: usize },
+    NoStartState,
+    MultipleStartStates { first: String, second: String },
+    ReservedName { name: String },
+    UnknownTarget { state: String, target: String },
+    TransitionCountMismatch { state: String, expected: usize, found: usize },
+    SymbolOutOfRange { state: String, symbol: char, declared: usize },
+    NameCollision { name: String },
+    DuplicateReadSymbol { state: String, symbol: char },
+    MissingReadSymbol { state: String, symbol: char },
+    IncludeCycle { path: String },
+    AlphabetMismatch { path: String, expected: usize, found: usize },
+    IncludeMissingStart { path: String },
+    IncludeNotFound { path: String },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ValidationError::StateCountMismatch { declared, parsed } => {
+                write!(f, "header declares {} state(s) but {} were parsed", declared, parsed)
+            },
+            ValidationError::AlphabetTooLarge { declared } => {
+                write!(f, "header declares {} symbol(s) but only {} are representable", declared, MAX_SYMBOLS)
+            },
+            ValidationError::NoStartState => write!(f, "no state is marked START"),
+            ValidationError::MultipleStartStates { ref first, ref second } => {
+                write!(f, "multiple states marked START: {} and {}", first, second)
+            },
+            ValidationError::ReservedName { ref name } => {
+                write!(f, "state {} uses a reserved name", name)
+            },
+            ValidationError::UnknownTarget { ref state, ref target } => {
+                write!(f, "state {} transitions to undeclared state {}", state, target)
+            },
+            ValidationError::TransitionCountMismatch { ref state, expected, found } => {
+                write!(f, "state {} has {} transition(s) but the alphabet has {} symbol(s)", state, found, expected)
+            },
+            ValidationError::SymbolOutOfRange { ref state, symbol, declared } => {
+                write!(f, "state {} references symbol {} outside the declared {}-symbol alphabet", state, symbol, declared)
+            },
+            ValidationError::NameCollision { ref name } => {
+                write!(f, "state {} is declared more than once", name)
+            },
+            ValidationError::DuplicateReadSymbol { ref state, symbol } => {
+                write!(f, "state {} has more than one transition reading symbol {}", state, symbol)
+            },
+            ValidationError::MissingReadSymbol { ref state, symbol } => {
+                write!(f, "state {} has no transition reading symbol {}", state, symbol)
+            },
+            ValidationError::IncludeCycle { ref path } => {
+                write!(f, "include cycle detected at {}", path)
+            },
+            ValidationError::AlphabetMismatch { ref path, expected, found } => {
+                write!(f, "included file {} declares a {}-symbol alphabet but the including machine uses {}", path, found, expected)
+            },
+            ValidationError::IncludeMissingStart { ref path } => {
+                write!(f, "included file {} has no START state to expose as an entry point", path)
+            },
+            ValidationError::IncludeNotFound { ref path } => {
+                write!(f, "included file {} could not be opened or read", path)
+            },
+        }
+    }
+}
+
 impl_rdp! {
     grammar! {
         whitespace = _{ [" "] | ["\t"] } // Magic
@@ -79,30 +183,41 @@ impl_rdp! {
 
         number = @{ (["0"] | ['1'..'9'] ~ ['0'..'9']*) }
         state_name = @{ (['a'..'z'] | ['A'..'Z'] | ["_"] | ['0'..'9'] | ["."])+ }
-        tm_alphabet = @{ ['a'..'b'] }
+        tm_alphabet = @{ ['a'..'z'] }
         head_direction = @{ ["R"] | ["L"] | ["-"] }
         start = @{ [i"START"] ~ whitespace }
+        quoted_path = @{ ["\""] ~ (!["\""] ~ any)* ~ ["\""] }
 
         transition = _{ tm_alphabet ~ ["->"] ~ state_name ~ [";"] ~ head_direction ~ [";"] ~ tm_alphabet ~ nl }
-        state = _{ start? ~ state_name ~ [":"] ~ nl ~ transition ~ transition ~ nl* }
-        header = _{ [i"states:"] ~ number ~ nl+ }
+        state = _{ start? ~ state_name ~ [":"] ~ nl ~ transition+ ~ nl* }
+        include_stmt = _{ [i"include"] ~ whitespace ~ quoted_path ~ whitespace ~ [i"as"] ~ whitespace ~ state_name ~ whitespace ~ ["->"] ~ whitespace ~ state_name ~ nl+ }
+        states_header = _{ [i"states:"] ~ number ~ nl+ }
+        symbols_header = _{ [i"symbols:"] ~ number ~ nl+ }
+        header = _{ include_stmt* ~ states_header ~ symbols_header }
     }
 
     process! {
         _transition(&self) -> TransitionInfo {
-            (_: tm_alphabet, &next: state_name, &mov: head_direction, &write: tm_alphabet) => {
+            (&read: tm_alphabet, &next: state_name, &mov: head_direction, &write: tm_alphabet) => {
+                let read = symbol_from_char(read.chars().next().unwrap());
                 let mov = match mov {
                     "R" => Direction::R,
                     "L" => Direction::L,
                     "-" => Direction::None,
                     _ => unreachable!(),
                 };
-                let write = match write {
-                    "a" => Symbol::A,
-                    "b" => Symbol::B,
-                    _ => unreachable!(),
-                };
-                TransitionInfo { next: next.to_string(), mov, write }
+                let write = symbol_from_char(write.chars().next().unwrap());
+                TransitionInfo { read, next: next.to_string(), mov, write }
+            },
+        }
+
+        _transitions(&self) -> Vec<TransitionInfo> {
+            (transition: _transition(), mut tail: _transitions()) => {
+                tail.insert(0, transition);
+                tail
+            },
+            () => {
+                Vec::new()
             },
         }
 
@@ -110,12 +225,11 @@ impl_rdp! {
             (_: start, state: _state()) => {
                 StateInfo { start: true, ..state }
             },
-            (&name: state_name, on_a: _transition(), on_b: _transition()) => {
+            (&name: state_name, transitions: _transitions()) => {
                 StateInfo {
                     start: false,
                     name: name.to_string(),
-                    on_a,
-                    on_b,
+                    transitions,
                 }
             },
         }
@@ -125,16 +239,242 @@ impl_rdp! {
                 number.parse().unwrap()
             },
         }
+
+        _include(&self) -> IncludeDirective {
+            (&path: quoted_path, &prefix: state_name, &exit: state_name) => {
+                IncludeDirective {
+                    path: path.trim_matches('"').to_string(),
+                    prefix: prefix.to_string(),
+                    exit: exit.to_string(),
+                }
+            },
+        }
+
+        _includes(&self) -> Vec<IncludeDirective> {
+            (include: _include(), mut tail: _includes()) => {
+                tail.insert(0, include);
+                tail
+            },
+            () => {
+                Vec::new()
+            },
+        }
     }
 }
 
-fn make_tm(buf: &str) -> (Tm, HashMap<StateNumber, String>) {
+/// Parses the includes, header and every state block the input offers,
+/// without assuming the header's count is trustworthy.
+fn parse_tm(buf: &str) -> (Vec<IncludeDirective>, usize, usize, Vec<StateInfo>) {
     let mut parser = Rdp::new(StringInput::new(buf));
     assert!(parser.header());
-    let states = parser._header();
+    let includes = parser._includes();
+    let declared_states = parser._header();
+    let declared_symbols = parser._header();
 
-    let mut tm: Tm = Default::default();
     let mut infos = Vec::new();
+    while parser.state() {
+        infos.push(parser._state());
+    }
+    (includes, declared_states, declared_symbols, infos)
+}
+
+/// Recursively loads every `include`d file relative to `base_dir`, renames
+/// its states under `PREFIX.`, and rewires any transition that would have
+/// left the subroutine (to one of the reserved names) to the including
+/// machine's declared exit state instead. `visiting` is the stack of files
+/// currently being expanded, so a file that includes itself (directly or
+/// transitively) is reported rather than recursing forever.
+fn resolve_includes(
+    base_dir: &Path,
+    includes: Vec<IncludeDirective>,
+    mut declared_states: usize,
+    declared_symbols: usize,
+    infos: Vec<StateInfo>,
+    visiting: &mut HashSet<PathBuf>,
+) -> Result<(usize, usize, Vec<StateInfo>), ValidationError> {
+    let mut all_infos = Vec::new();
+
+    for include in includes {
+        let joined = base_dir.join(&include.path);
+        // Canonicalize before keying `visiting`: two include sites can reach
+        // the same file through syntactically different relative paths, and
+        // only the canonical form reliably catches that as the same file.
+        let canonical = joined.canonicalize()
+            .map_err(|_| ValidationError::IncludeNotFound { path: include.path.clone() })?;
+        if !visiting.insert(canonical.clone()) {
+            return Err(ValidationError::IncludeCycle { path: include.path.clone() });
+        }
+
+        let mut buf = String::new();
+        File::open(&canonical)
+            .and_then(|mut file| file.read_to_string(&mut buf))
+            .map_err(|_| ValidationError::IncludeNotFound { path: include.path.clone() })?;
+        let (sub_includes, sub_states, sub_symbols, sub_infos) = parse_tm(&buf);
+        let sub_base_dir = canonical.parent().unwrap_or_else(|| Path::new(".")).to_path_buf();
+        let (resolved_states, resolved_symbols, resolved_infos) =
+            resolve_includes(&sub_base_dir, sub_includes, sub_states, sub_symbols, sub_infos, visiting)?;
+
+        visiting.remove(&canonical);
+
+        if resolved_symbols != declared_symbols {
+            return Err(ValidationError::AlphabetMismatch {
+                path: include.path.clone(),
+                expected: declared_symbols,
+                found: resolved_symbols,
+            });
+        }
+
+        let mut entry_target = None;
+        for mut info in resolved_infos {
+            info.name = format!("{}.{}", include.prefix, info.name);
+            if info.start {
+                if let Some(first) = entry_target {
+                    return Err(ValidationError::MultipleStartStates {
+                        first,
+                        second: info.name.clone(),
+                    });
+                }
+                entry_target = Some(info.name.clone());
+            }
+            info.start = false;
+            for transition in &mut info.transitions {
+                transition.next = if RESERVED_NAMES.contains(&transition.next.as_str()) {
+                    include.exit.clone()
+                } else {
+                    format!("{}.{}", include.prefix, transition.next)
+                };
+            }
+            all_infos.push(info);
+        }
+
+        let entry_target = entry_target.ok_or_else(|| ValidationError::IncludeMissingStart {
+            path: include.path.clone(),
+        })?;
+
+        // Expose the subroutine's entry point as `PREFIX` itself, so callers
+        // can target it directly instead of reaching into `PREFIX.<name>`.
+        all_infos.push(StateInfo {
+            name: include.prefix.clone(),
+            start: false,
+            transitions: (0..declared_symbols).map(|symbol| TransitionInfo {
+                read: symbol,
+                next: entry_target.clone(),
+                mov: Direction::None,
+                write: symbol,
+            }).collect(),
+        });
+
+        declared_states += resolved_states + 1;
+    }
+
+    all_infos.extend(infos);
+    Ok((declared_states, declared_symbols, all_infos))
+}
+
+/// Checks the parsed machine for every problem `link_tm` would otherwise
+/// discover one at a time via panics, so `check` can report them all at once.
+fn validate(declared_states: usize, declared_symbols: usize, infos: &[StateInfo]) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+
+    if declared_states != infos.len() {
+        errors.push(ValidationError::StateCountMismatch {
+            declared: declared_states,
+            parsed: infos.len(),
+        });
+    }
+
+    if declared_symbols > MAX_SYMBOLS {
+        errors.push(ValidationError::AlphabetTooLarge { declared: declared_symbols });
+    }
+
+    for info in infos {
+        if RESERVED_NAMES.contains(&info.name.as_str()) {
+            errors.push(ValidationError::ReservedName { name: info.name.clone() });
+        }
+
+        if info.transitions.len() != declared_symbols {
+            errors.push(ValidationError::TransitionCountMismatch {
+                state: info.name.clone(),
+                expected: declared_symbols,
+                found: info.transitions.len(),
+            });
+        }
+
+        let mut seen_reads = vec![false; declared_symbols];
+        for transition in &info.transitions {
+            if transition.write >= declared_symbols {
+                errors.push(ValidationError::SymbolOutOfRange {
+                    state: info.name.clone(),
+                    symbol: char_from_symbol(transition.write),
+                    declared: declared_symbols,
+                });
+            }
+
+            if transition.read >= declared_symbols {
+                errors.push(ValidationError::SymbolOutOfRange {
+                    state: info.name.clone(),
+                    symbol: char_from_symbol(transition.read),
+                    declared: declared_symbols,
+                });
+            } else if seen_reads[transition.read] {
+                errors.push(ValidationError::DuplicateReadSymbol {
+                    state: info.name.clone(),
+                    symbol: char_from_symbol(transition.read),
+                });
+            } else {
+                seen_reads[transition.read] = true;
+            }
+        }
+        for (symbol, seen) in seen_reads.iter().enumerate() {
+            if !seen {
+                errors.push(ValidationError::MissingReadSymbol {
+                    state: info.name.clone(),
+                    symbol: char_from_symbol(symbol),
+                });
+            }
+        }
+    }
+
+    let mut name_counts: HashMap<&str, usize> = HashMap::new();
+    for info in infos {
+        *name_counts.entry(info.name.as_str()).or_insert(0) += 1;
+    }
+    for (name, count) in &name_counts {
+        if *count > 1 {
+            errors.push(ValidationError::NameCollision { name: (*name).to_string() });
+        }
+    }
+
+    let mut start_states = infos.iter().filter(|info| info.start).map(|info| &info.name);
+    match (start_states.next(), start_states.next()) {
+        (None, _) => errors.push(ValidationError::NoStartState),
+        (Some(first), Some(second)) => errors.push(ValidationError::MultipleStartStates {
+            first: first.clone(),
+            second: second.clone(),
+        }),
+        _ => {},
+    }
+
+    let known_names: HashSet<&str> = RESERVED_NAMES.iter().cloned()
+        .chain(infos.iter().map(|info| info.name.as_str()))
+        .collect();
+    for info in infos {
+        for transition in &info.transitions {
+            if !known_names.contains(transition.next.as_str()) {
+                errors.push(ValidationError::UnknownTarget {
+                    state: info.name.clone(),
+                    target: transition.next.clone(),
+                });
+            }
+        }
+    }
+
+    errors
+}
+
+/// Builds the executable `Tm` from already-validated state infos.
+fn link_tm(declared_symbols: usize, infos: Vec<StateInfo>) -> (Tm, HashMap<StateNumber, String>) {
+    let mut tm: Tm = Default::default();
     let mut name_map = HashMap::new();
     let mut state_map = HashMap::new();
 
@@ -146,43 +486,38 @@ fn make_tm(buf: &str) -> (Tm, HashMap<StateNumber, String>) {
 
     for i in 0..name_map.len() {
         tm.states.push(State {
-            on_a: Transition {
-                mov: Direction::None,
-                write: Symbol::A,
-                next: i,
-            },
-            on_b: Transition {
+            transitions: (0..declared_symbols).map(|symbol| Transition {
                 mov: Direction::None,
-                write: Symbol::B,
+                write: symbol,
                 next: i,
-            }
+            }).collect(),
         })
     }
 
-    for _ in 0..states {
-        assert!(parser.state());
-        let info = parser._state();
+    for info in &infos {
         let state_num = name_map.len();
         name_map.insert(info.name.clone(), state_num);
         if info.start {
-            assert!(tm.start_state.is_none());
             tm.start_state = Some(state_num);
         }
-        infos.push(info);
     }
 
     for info in infos {
+        // Indexed by the parsed read-symbol rather than file order, so a
+        // validated state's transitions land in the right alphabet slot
+        // regardless of the order they were written in.
+        let mut transitions: Vec<Option<Transition>> = (0..declared_symbols).map(|_| None).collect();
+        for transition in info.transitions {
+            transitions[transition.read] = Some(Transition {
+                mov: transition.mov,
+                write: transition.write,
+                next: name_map[&transition.next],
+            });
+        }
         tm.states.push(State {
-            on_a: Transition {
-                mov: info.on_a.mov,
-                write: info.on_a.write,
-                next: name_map[&info.on_a.next],
-            },
-            on_b: Transition {
-                mov: info.on_b.mov,
-                write: info.on_b.write,
-                next: name_map[&info.on_b.next],
-            },
+            transitions: transitions.into_iter()
+                .map(|transition| transition.expect("validate() guarantees one transition per symbol"))
+                .collect(),
         })
     }
 
@@ -192,23 +527,173 @@ fn make_tm(buf: &str) -> (Tm, HashMap<StateNumber, String>) {
     (tm, state_map)
 }
 
-fn main() {
-    let args = Docopt::new(USAGE)
-        .and_then(|d| d.parse())
-        .unwrap_or_else(|e| e.exit());
+/// A tape that grows in both directions: a left move past the current
+/// origin transparently prepends a blank cell instead of underflowing.
+/// `ix` is the true, unbounded tape position; it never needs to be clamped
+/// or asserted non-negative.
+struct Tape {
+    cells: VecDeque<Symbol>,
+    origin: isize,
+}
 
-    let mut buf = String::new();
-    let mut fd = File::open(args.get_str("<tm2>")).expect("open file");
-    fd.read_to_string(&mut buf).expect("readable");
-    let (tm, state_map) = make_tm(&buf);
+impl Tape {
+    fn new() -> Tape {
+        Tape { cells: VecDeque::new(), origin: 0 }
+    }
+
+    fn ensure(&mut self, ix: isize) {
+        if self.cells.is_empty() {
+            self.cells.push_back(BLANK);
+            self.origin = ix;
+        }
+        while ix < self.origin {
+            self.cells.push_front(BLANK);
+            self.origin -= 1;
+        }
+        while ix >= self.origin + self.cells.len() as isize {
+            self.cells.push_back(BLANK);
+        }
+    }
+
+    fn get(&mut self, ix: isize) -> Symbol {
+        self.ensure(ix);
+        self.cells[(ix - self.origin) as usize]
+    }
+
+    fn set(&mut self, ix: isize, value: Symbol) {
+        self.ensure(ix);
+        let physical = (ix - self.origin) as usize;
+        self.cells[physical] = value;
+    }
+
+    /// The current contents as one contiguous slice, in tape order.
+    fn contents(&mut self) -> &[Symbol] {
+        self.cells.make_contiguous()
+    }
+
+    fn physical(&self, ix: isize) -> usize {
+        (ix - self.origin) as usize
+    }
+}
+
+/// A configuration normalized so that two runs separated only by an
+/// untouched stretch of blank tape compare equal: the window is the slice
+/// between the leftmost and rightmost non-blank cells, and the offset is the
+/// head's position relative to that window rather than to the tape origin.
+/// `tape_ix` is the true (unbounded) position, kept only to report net shift.
+struct NormalizedConfig {
+    state: StateNumber,
+    offset: isize,
+    window: Vec<Symbol>,
+    tape_ix: isize,
+}
+
+// `tape_ix` is deliberately excluded: two configurations reached by a loop
+// that nets a translation are still the same loop, and `tape_ix` is carried
+// along only so the caller can report that net shift once a match is found.
+impl PartialEq for NormalizedConfig {
+    fn eq(&self, other: &NormalizedConfig) -> bool {
+        self.state == other.state && self.offset == other.offset && self.window == other.window
+    }
+}
+
+/// Indices of the leftmost and rightmost non-blank cells, or `None` if the
+/// whole tape is blank.
+fn non_blank_window(tape: &[Symbol]) -> Option<(usize, usize)> {
+    let left = tape.iter().position(|&symbol| symbol != BLANK)?;
+    let right = tape.iter().rposition(|&symbol| symbol != BLANK).unwrap();
+    Some((left, right))
+}
+
+fn normalize(state: StateNumber, tape_ix: isize, physical_ix: usize, tape: &[Symbol]) -> NormalizedConfig {
+    let (offset, window) = match non_blank_window(tape) {
+        // Guard the all-blank tape: there is no window to be relative to, so
+        // pin the offset at 0 rather than reading past either edge.
+        None => (0, Vec::new()),
+        Some((left, right)) => (physical_ix as isize - left as isize, tape[left..=right].to_vec()),
+    };
+    NormalizedConfig { state, offset, window, tape_ix }
+}
+
+/// Run-length encodes the final non-blank tape region, e.g. `1b2a1b` for
+/// `baab` (`a` is blank, but an internal blank run is still part of the
+/// window between the outermost non-blank cells). Empty if the tape is all
+/// blank.
+fn dump_tape(tape: &[Symbol]) -> String {
+    let (left, right) = match non_blank_window(tape) {
+        Some(window) => window,
+        None => return String::new(),
+    };
+
+    let mut out = String::new();
+    let mut i = left;
+    while i <= right {
+        let symbol = tape[i];
+        let mut run = 1;
+        while i + run <= right && tape[i + run] == symbol {
+            run += 1;
+        }
+        out.push_str(&run.to_string());
+        out.push(char_from_symbol(symbol));
+        i += run;
+    }
+    out
+}
+
+/// Brent-style cycle detection over normalized configurations: a saved
+/// reference is recaptured each time the number of steps since the last
+/// capture reaches the next power of two (after steps 1, 3, 7, 15, ...) and
+/// compared against every subsequent step, so a match proves the machine has entered a loop
+/// (possibly net-translating) it can never halt out of.
+struct LoopDetector {
+    reference: Option<NormalizedConfig>,
+    power: usize,
+    lam: usize,
+}
+
+impl LoopDetector {
+    fn new() -> LoopDetector {
+        LoopDetector { reference: None, power: 1, lam: 0 }
+    }
 
-    let verbose = args.get_bool("-v");
-    let mut tape = Vec::new();
-    let mut tape_ix = 2;
+    /// Feeds the configuration reached after one completed step. Returns the
+    /// loop length and net tape shift once it proves non-termination.
+    fn step(&mut self, state: StateNumber, tape_ix: isize, physical_ix: usize, tape: &[Symbol]) -> Option<(usize, i64)> {
+        self.lam += 1;
+        let current = normalize(state, tape_ix, physical_ix, tape);
+
+        // Compare against the reference saved by a *previous* call before
+        // possibly overwriting it below — comparing against a reference
+        // just captured from this very call would trivially always match.
+        let matched = match self.reference {
+            Some(ref reference) if *reference == current => {
+                Some((self.lam, (current.tape_ix - reference.tape_ix) as i64))
+            },
+            _ => None,
+        };
+        if matched.is_some() {
+            return matched;
+        }
+
+        if self.power == self.lam {
+            self.power *= 2;
+            self.lam = 0;
+            self.reference = Some(current);
+        }
+        None
+    }
+}
+
+fn run(tm: Tm, state_map: HashMap<StateNumber, String>, verbose: bool, detect_loops: bool, dump_tape_flag: bool) {
+    let mut tape = Tape::new();
+    let mut tape_ix: isize = 2;
     let mut current_state = tm.start_state.expect("starting state");
+    let mut min_tape_ix = tape_ix;
+    let mut max_tape_ix = tape_ix;
 
     let mut overall_step_count = BigUint::zero();
     let mut step_count = 0usize;
+    let mut detector = if detect_loops { Some(LoopDetector::new()) } else { None };
     println!("{:>24} {}", 0, state_map[&current_state]);
     loop {
         step_count += 1;
@@ -218,18 +703,10 @@ fn main() {
             println!("{:>24} {}", overall_step_count, state_map[&current_state]);
         }
 
-        if tape_ix >= tape.len() {
-            tape.resize(tape_ix * 2, Symbol::A);
-        }
-
         let state = &tm.states[current_state];
-        let transition = if tape[tape_ix] == Symbol::A {
-            &state.on_a
-        } else {
-            &state.on_b
-        };
+        let transition = &state.transitions[tape.get(tape_ix)];
 
-        tape[tape_ix] = transition.write;
+        tape.set(tape_ix, transition.write);
         current_state = transition.next;
 
         if current_state < 5 {
@@ -237,13 +714,138 @@ fn main() {
         }
 
         match transition.mov {
-            Direction::L => {
-                debug_assert!(tape_ix > 0);
-                tape_ix -= 1
-            },
+            Direction::L => tape_ix -= 1,
             Direction::R => tape_ix += 1,
             Direction::None => {},
         };
+        min_tape_ix = min_tape_ix.min(tape_ix);
+        max_tape_ix = max_tape_ix.max(tape_ix);
+
+        if let Some(ref mut detector) = detector {
+            tape.ensure(tape_ix);
+            let physical_ix = tape.physical(tape_ix);
+            if let Some((length, shift)) = detector.step(current_state, tape_ix, physical_ix, tape.contents()) {
+                println!("NONHALTING (loop of length {}, net shift {})", length, shift);
+                return;
+            }
+        }
     }
     println!("{:>24} {}", overall_step_count + BigUint::from(step_count), state_map[&current_state]);
+
+    let score = tape.contents().iter().filter(|&&symbol| symbol != BLANK).count();
+    let space = (max_tape_ix - min_tape_ix + 1) as usize;
+    println!("score (sigma): {}", score);
+    println!("space (S): {}", space);
+    if dump_tape_flag {
+        println!("tape: {}", dump_tape(tape.contents()));
+    }
+}
+
+/// Reads the machine description from the given path, or from stdin when
+/// the path is empty (not given) or `-`.
+fn read_input(path: &str) -> String {
+    let mut buf = String::new();
+    if path.is_empty() || path == "-" {
+        io::stdin().read_to_string(&mut buf).expect("readable stdin");
+    } else {
+        File::open(path).expect("open file").read_to_string(&mut buf).expect("readable");
+    }
+    buf
+}
+
+fn main() {
+    let args = Docopt::new(USAGE)
+        .and_then(|d| d.parse())
+        .unwrap_or_else(|e| e.exit());
+
+    let path = args.get_str("<tm2>");
+    let base_dir = if path.is_empty() || path == "-" {
+        PathBuf::from(".")
+    } else {
+        Path::new(path).parent().filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."))
+            .to_path_buf()
+    };
+
+    let buf = read_input(path);
+    let (includes, declared_states, declared_symbols, infos) = parse_tm(&buf);
+
+    let mut visiting = HashSet::new();
+    let (declared_states, declared_symbols, infos) =
+        match resolve_includes(&base_dir, includes, declared_states, declared_symbols, infos, &mut visiting) {
+            Ok(result) => result,
+            Err(error) => {
+                eprintln!("error: {}", error);
+                process::exit(1);
+            },
+        };
+
+    let errors = validate(declared_states, declared_symbols, &infos);
+    if !errors.is_empty() {
+        for error in &errors {
+            eprintln!("error: {}", error);
+        }
+        process::exit(1);
+    }
+
+    if args.get_bool("check") {
+        return;
+    }
+
+    let (tm, state_map) = link_tm(declared_symbols, infos);
+    run(tm, state_map, args.get_bool("-v"), args.get_bool("--detect-loops"), args.get_bool("--dump-tape"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_a_periodic_loop_over_an_unchanging_tape() {
+        let mut detector = LoopDetector::new();
+        let tape = vec![1, 2, 1];
+        let mut result = None;
+        for step in 0..50 {
+            result = detector.step(step % 3, 2, 1, &tape);
+            if result.is_some() {
+                break;
+            }
+        }
+        let (length, shift) = result.expect("a state cycle over an unchanging tape is non-halting");
+        assert!(length > 0);
+        assert_eq!(shift, 0);
+    }
+
+    #[test]
+    fn detects_a_translating_loop() {
+        // State bounces between two values while the head keeps moving the
+        // same direction every step: the tape stays blank throughout, so the
+        // normalized window never changes even though `tape_ix` grows without
+        // bound. This is the "possibly net-translating" case `LoopDetector`
+        // is documented to catch.
+        let mut detector = LoopDetector::new();
+        let tape = vec![BLANK];
+        let mut result = None;
+        for step in 0..100 {
+            let tape_ix = step as isize;
+            result = detector.step(step % 2, tape_ix, 0, &tape);
+            if result.is_some() {
+                break;
+            }
+        }
+        let (_, shift) = result.expect("a periodic state cycle with a net shift is still non-halting");
+        assert!(shift > 0);
+    }
+
+    #[test]
+    fn does_not_flag_a_monotonically_growing_tape() {
+        let mut detector = LoopDetector::new();
+        for step in 0..200usize {
+            let mut tape = vec![BLANK; step + 2];
+            for cell in &mut tape[..=step] {
+                *cell = 1;
+            }
+            assert!(detector.step(0, step as isize, step, &tape).is_none());
+        }
+    }
 }